@@ -4,11 +4,12 @@ extern crate bincode;
 extern crate time;
 extern crate getopts;
 extern crate regex;
+extern crate fs2;
 
 use std::io::prelude::*;
-use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 use std::str::FromStr;
 use std::cmp::Ordering;
 use std::io::ErrorKind;
@@ -18,23 +19,67 @@ use std::env;
 use std::fs;
 use std::fs::OpenOptions;
 use std::fs::File;
-use bincode::{serialize_into, deserialize_from};
+use std::mem;
+use std::fmt;
+use bincode::{deserialize, serialize_into};
 use time::get_time;
 use getopts::Options;
 use regex::Regex;
+use fs2::FileExt;
 use failure::{Error, ResultExt};
 
+// header marking a versioned database, as opposed to a legacy headerless dump
+const MAGIC: &[u8; 4] = b"FDB\x01";
+const FORMAT_VERSION: u32 = 2;
+const DEFAULT_BACKUPS: u32 = 2; // FDB_BACKUPS
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct Item {
-    path: String,
+    path: String, // owned, not borrowed from the load buffer: not worth an unsafe 'static per-query win
     atime: i64, // unix time of last access
     hits: u32,
+    rank: f32, // zoxide-style aging score, see `age`
+}
+
+// pre-`rank` layout (format versions 0 and 1)
+#[derive(Serialize, Deserialize)]
+struct ItemV1 {
+    path: String,
+    atime: i64,
+    hits: u32,
+}
+
+impl From<ItemV1> for Item {
+    fn from(old: ItemV1) -> Item {
+        Item {
+            path: old.path,
+            atime: old.atime,
+            hits: old.hits,
+            rank: old.hits as f32,
+        }
+    }
 }
 
 struct Settings {
-    history_size: usize,
+    max_age: f32, // ceiling on summed rank before `age` renormalizes; 0 disables aging
     db_path: String,
     sort_by: SortBy,
+    check_exists: bool, // prune paths that no longer exist on disk when querying
+    match_mode: MatchMode,
+}
+
+#[derive(Copy, Clone)]
+enum MatchMode {
+    Regex,
+    Keyword,
+}
+
+fn parse_match_mode(name: &str) -> Option<MatchMode> {
+    match name {
+        "regex" => Some(MatchMode::Regex),
+        "keyword" => Some(MatchMode::Keyword),
+        _ => None,
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -42,6 +87,7 @@ enum Action {
     Query,
     Add,
     Delete,
+    Upgrade,
 }
 
 #[derive(Copy, Clone)]
@@ -49,53 +95,65 @@ enum SortBy {
     Frecency,
     Atime,
     Hits,
+    Rank,
 }
 
-struct Lock(PathBuf);
+// advisory lock (`flock`) on a `.lock` sidecar file; `timeout` bounds the wait
+struct Lock(File);
 
 impl Lock {
-    pub fn new(path: &str) -> Result<Lock, Error> {
-        let path = PathBuf::from(format!("{}.lock", path));
-        while path.exists() {
-            thread::sleep(Duration::from_millis(30));
-        }
-        let mut file = OpenOptions::new().write(true).create_new(true).open(&path);
-        while let Err(e) = file {
-            if e.kind() == ErrorKind::AlreadyExists {
-                while path.exists() {
-                    thread::sleep(Duration::from_millis(30));
+    pub fn new(path: &str, timeout: Option<Duration>) -> Result<Lock, Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(format!("{}.lock", path))
+            .context("Can't open the lock file")?;
+        match timeout {
+            None => file.lock_exclusive().context("Can't lock database")?,
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    match file.try_lock_exclusive() {
+                        Ok(()) => break,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            if Instant::now() >= deadline {
+                                return Err(format_err!("Timed out waiting for the database lock"));
+                            }
+                            thread::sleep(Duration::from_millis(30));
+                        }
+                        Err(e) => return Err(Error::from(e).context("Can't lock database").into()),
+                    }
                 }
-            } else {
-                return Err(Error::from(e).context("Can't create the lock file").into());
             }
-            file = OpenOptions::new().write(true).create_new(true).open(&path);
         }
-        Ok(Lock(path))
+        Ok(Lock(file))
     }
 }
 
 impl Drop for Lock {
     fn drop(&mut self) {
-        let _ = fs::remove_file(&self.0);
+        let _ = self.0.unlock();
     }
 }
 
+fn frecency(atime: i64, hits: u32) -> f32 {
+    let age = (get_time().sec - atime) as f32;
+    (hits as f32) / (0.25 + 3e-6 * age)
+}
+
 impl Item {
     fn new(path: &str) -> Item {
         Item {
             path: path.to_string(),
             atime: get_time().sec,
             hits: 1,
+            rank: 1.0,
         }
     }
 
-    fn frecency(&self) -> f32 {
-        let age = (get_time().sec - self.atime) as f32;
-        (self.hits as f32) / (0.25 + 3e-6 * age)
-    }
-
     fn touch(&mut self) {
         self.hits += 1;
+        self.rank += 1.0;
         self.atime = get_time().sec;
     }
 }
@@ -112,10 +170,20 @@ fn parse_sort_method(name: &str) -> Option<SortBy> {
         "frecency" => Some(SortBy::Frecency),
         "atime" => Some(SortBy::Atime),
         "hits" => Some(SortBy::Hits),
+        "rank" => Some(SortBy::Rank),
         _ => None,
     }
 }
 
+// Duration::from_secs_f64 panics on negative/infinite/NaN, so validate first
+fn parse_timeout(raw: &str) -> Result<Duration, Error> {
+    let secs = raw.parse::<f64>().context("Invalid --timeout value")?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(format_err!("Invalid --timeout value: {} is not a non-negative number", secs));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
 fn print_version() {
     println!("{}", option_env!("CARGO_PKG_VERSION").unwrap_or("Unknown"));
 }
@@ -123,38 +191,125 @@ fn print_version() {
 fn print_usage(opts: &Options) {
     println!(
         "{}",
-        opts.usage("Usage: fdb [-i DB_PATH] [-u] [-s SORT_BY] -h|-v|-z|-q PATTERN ...|-a PATH ...|-d PATH ...")
+        opts.usage("Usage: fdb [-i DB_PATH] [-u] [-s SORT_BY] [--timeout SECONDS] -h|-v|-z|--upgrade|-q [-e] PATTERN ...|-a PATH ...|-d PATH ...")
     );
 }
 
-fn load_data(path: &str) -> Result<Vec<Item>, Error> {
+fn read_bytes(path: &str) -> Result<Vec<u8>, Error> {
     let mut f = File::open(path).context("Can't open data file")?;
-    deserialize_from(&mut f).context("Can't deserialize data").map_err(Into::into)
+    let mut buffer = Vec::new();
+    f.read_to_end(&mut buffer).context("Can't read data file")?;
+    Ok(buffer)
+}
+
+// returns (format version, payload offset); version 0 means no recognized header
+fn parse_header(buffer: &[u8]) -> (u32, usize) {
+    let header_len = MAGIC.len() + mem::size_of::<u32>();
+    if buffer.len() < header_len || buffer[..MAGIC.len()] != MAGIC[..] {
+        return (0, 0);
+    }
+    match deserialize::<u32>(&buffer[MAGIC.len()..header_len]) {
+        Ok(version) => (version, header_len),
+        Err(_) => (0, 0),
+    }
+}
+
+// lets `load_data` tell "newer fdb wrote this" apart from ordinary corruption
+#[derive(Debug)]
+struct UnsupportedFormatVersion {
+    found: u32,
+    supported: u32,
+}
+
+impl fmt::Display for UnsupportedFormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Database format version {} is newer than this build supports ({})", self.found, self.supported)
+    }
+}
+
+impl failure::Fail for UnsupportedFormatVersion {}
+
+fn migrate(version: u32, payload: &[u8]) -> Result<Vec<Item>, Error> {
+    if version > FORMAT_VERSION {
+        return Err(UnsupportedFormatVersion { found: version, supported: FORMAT_VERSION }.into());
+    }
+    match version {
+        0 | 1 => {
+            let data: Vec<ItemV1> = deserialize(payload).context("Can't deserialize data")?;
+            Ok(data.into_iter().map(Item::from).collect())
+        }
+        _ => deserialize(payload).context("Can't deserialize data").map_err(Into::into),
+    }
+}
+
+fn load_data_from_bytes(buffer: &[u8]) -> Result<Vec<Item>, Error> {
+    let (version, offset) = parse_header(buffer);
+    migrate(version, &buffer[offset..])
+}
+
+fn load_data(path: &str) -> Result<Vec<Item>, Error> {
+    match read_bytes(path).and_then(|buffer| load_data_from_bytes(&buffer)) {
+        Ok(data) => Ok(data),
+        Err(e) => {
+            if e.downcast_ref::<UnsupportedFormatVersion>().is_some() {
+                return Err(e);
+            }
+            let backups = get_env::<u32>("FDB_BACKUPS", DEFAULT_BACKUPS);
+            for i in 1..=backups {
+                let bak = format!("{}.bak.{}", path, i);
+                if let Ok(data) = read_bytes(&bak).and_then(|buffer| load_data_from_bytes(&buffer)) {
+                    eprintln!("fdb: database corrupt, recovered from {}.", bak);
+                    return Ok(data);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+// shifts .bak.N generations up by one and moves path into .bak.1
+fn rotate_backups(path: &str, backups: u32) -> Result<(), Error> {
+    if backups == 0 {
+        return Ok(());
+    }
+    for i in (1..backups).rev() {
+        let from = format!("{}.bak.{}", path, i);
+        let to = format!("{}.bak.{}", path, i + 1);
+        match fs::rename(&from, &to) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::from(e).context("Can't rotate database backup").into()),
+        }
+    }
+    match fs::rename(path, format!("{}.bak.1", path)) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::from(e).context("Can't rotate database backup").into()),
+    }
 }
 
 fn save_data(data: &[Item], path: &str) -> Result<(), Error> {
     let new_path = path.to_string() + ".tmp";
     let mut file = File::create(&new_path)?;
+    file.write_all(MAGIC).context("Can't write database header")?;
+    serialize_into(&mut file, &FORMAT_VERSION).context("Can't write database format version")?;
     serialize_into(&mut file, data).context("Can't serialize data into the database")?;
     file.flush().context("Couldn't flush temporary database file")?;
+    rotate_backups(path, get_env::<u32>("FDB_BACKUPS", DEFAULT_BACKUPS))?;
     fs::rename(new_path, path).context("Couldn't rename temporary data file").map_err(Into::into)
 }
 
-fn cmd_sort(sort_by: SortBy, data: &mut Vec<Item>) {
+fn cmd_sort(sort_by: SortBy, data: &mut [Item]) {
     match sort_by {
-        SortBy::Frecency => data.sort_by(sort_method_frecency),
+        SortBy::Frecency => data.sort_by(|a, b| {
+            frecency(a.atime, a.hits).partial_cmp(&frecency(b.atime, b.hits)).unwrap_or(Ordering::Equal).reverse()
+        }),
         SortBy::Atime => data.sort_by(|a, b| a.atime.cmp(&b.atime).reverse()),
         SortBy::Hits => data.sort_by(|a, b| a.hits.cmp(&b.hits).reverse()),
+        SortBy::Rank => data.sort_by(|a, b| a.rank.partial_cmp(&b.rank).unwrap_or(Ordering::Equal).reverse()),
     }
 }
 
-fn sort_method_frecency(a: &Item, b: &Item) -> Ordering {
-    a.frecency()
-        .partial_cmp(&b.frecency())
-        .unwrap_or(Ordering::Equal)
-        .reverse()
-}
-
 fn cmd_add(settings: &Settings, data: &mut Vec<Item>, paths: &[String]) {
     for path in paths.iter() {
         {
@@ -166,11 +321,19 @@ fn cmd_add(settings: &Settings, data: &mut Vec<Item>, paths: &[String]) {
         }
         data.push(Item::new(path));
     }
-    if settings.history_size > 0 && data.len() > settings.history_size {
-        cmd_sort(SortBy::Frecency, data);
-        while data.len() > settings.history_size {
-            data.pop();
+    if settings.max_age > 0.0 {
+        age(data, settings.max_age);
+    }
+}
+
+fn age(data: &mut Vec<Item>, max_age: f32) {
+    let sum: f32 = data.iter().map(|a| a.rank).sum();
+    if sum > max_age {
+        let factor = max_age / sum;
+        for item in data.iter_mut() {
+            item.rank *= factor;
         }
+        data.retain(|a| a.rank >= 1.0);
     }
 }
 
@@ -178,30 +341,103 @@ fn cmd_delete(data: &mut Vec<Item>, paths: &[String]) {
     data.retain(|a| paths.iter().find(|&p| a.path == *p).is_none());
 }
 
-fn cmd_query(sort_by: SortBy, data: &mut Vec<Item>, pattern: &str) -> Result<(), Error> {
-    let re = Regex::new(pattern).context("Couldn't create query regex")?;
+enum Matcher {
+    Regex(Regex),
+    Keyword(Vec<String>),
+}
+
+impl Matcher {
+    fn new(mode: MatchMode, free: &[String]) -> Result<Matcher, Error> {
+        match mode {
+            MatchMode::Regex => {
+                let re = Regex::new(&free.join(".*")).context("Couldn't create query regex")?;
+                Ok(Matcher::Regex(re))
+            }
+            MatchMode::Keyword => Ok(Matcher::Keyword(free.to_vec())),
+        }
+    }
+
+    fn is_match(&self, path: &str) -> bool {
+        match *self {
+            Matcher::Regex(ref re) => re.is_match(path),
+            Matcher::Keyword(ref keywords) => match_keywords(keywords, path),
+        }
+    }
+}
+
+// keywords must appear in order; the last one must land in the basename
+fn match_keywords(keywords: &[String], path: &str) -> bool {
+    if keywords.is_empty() {
+        return true;
+    }
+    let basename_start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let last = keywords.len() - 1;
+    let mut cursor = 0;
+    for (i, keyword) in keywords.iter().enumerate() {
+        let search_start = if i == last { cursor.max(basename_start) } else { cursor };
+        let case_sensitive = keyword.chars().any(|c| c.is_uppercase());
+        match find_keyword(&path[search_start..], keyword, case_sensitive) {
+            Some(offset) => cursor = search_start + offset + keyword.len(),
+            None => return false,
+        }
+    }
+    true
+}
+
+// ASCII-only case fold, unlike to_lowercase(), so byte offsets into haystack stay valid
+fn find_keyword(haystack: &str, needle: &str, case_sensitive: bool) -> Option<usize> {
+    if case_sensitive {
+        return haystack.find(needle);
+    }
+    if needle.is_empty() {
+        return Some(0);
+    }
+    let haystack = haystack.as_bytes();
+    let needle = needle.as_bytes();
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    (0..=haystack.len() - needle.len()).find(|&start| {
+        haystack[start..start + needle.len()]
+            .iter()
+            .zip(needle)
+            .all(|(&h, &n)| h.eq_ignore_ascii_case(&n))
+    })
+}
+
+// returns whether anything was pruned, so the caller knows whether to save
+fn cmd_query(sort_by: SortBy, data: &mut Vec<Item>, matcher: &Matcher, check_exists: bool) -> Result<bool, Error> {
     let mut stdout = stdout();
     cmd_sort(sort_by, data);
-    for item in data.iter() {
-        if re.is_match(&item.path) {
+    let mut pruned = false;
+    let mut broken_pipe = false;
+    data.retain(|item| {
+        if check_exists && fs::metadata(&item.path).is_err() {
+            pruned = true;
+            return false;
+        }
+        if !broken_pipe && matcher.is_match(&item.path) {
             // avoid panicking on `fdb -q PATTERN | head -n 1`
             if let Err(e) = writeln!(&mut stdout, "{}", item.path) {
                 if e.kind() == ErrorKind::BrokenPipe {
-                    break;
+                    broken_pipe = true;
                 } else {
                     panic!("Couldn't write to stdout: {:?}.", e);
                 }
             }
         }
-    }
-    Ok(())
+        true
+    });
+    Ok(pruned)
 }
 
 fn run() -> Result<(), Error> {
     let mut settings = Settings {
-        history_size: 600,
+        max_age: 9000.0,
         db_path: "~/.z".to_string(),
         sort_by: SortBy::Frecency,
+        check_exists: false,
+        match_mode: MatchMode::Regex,
     };
 
     let args: Vec<String> = env::args().skip(1).collect();
@@ -209,10 +445,12 @@ fn run() -> Result<(), Error> {
     let mut opts = Options::new();
 
     opts.optflag("q", "query", "Query for patterns in the database.");
+    opts.optflag("e", "existing", "Only show paths that still exist, pruning stale entries.");
     opts.optflag("a", "add", "Add paths to the database.");
     opts.optflag("d", "delete", "Delete paths from the database.");
-    opts.optflag("u", "unlimited", "Don't limit the size of the database.");
+    opts.optflag("u", "unlimited", "Don't age-prune the database.");
     opts.optflag("z", "initialize", "Initialize the database.");
+    opts.optflag("", "upgrade", "Migrate the database to the latest format and rewrite it.");
     opts.optflag("h", "help", "Print this help message.");
     opts.optflag("v", "version", "Print the version number.");
     opts.optopt("i", "db-path", "Use the given database.", "DB_PATH");
@@ -220,8 +458,10 @@ fn run() -> Result<(), Error> {
         "s",
         "sort-by",
         "Use the given sort method.",
-        "frecency|atime|hits",
+        "frecency|atime|hits|rank",
     );
+    opts.optopt("", "match", "Use the given match mode.", "regex|keyword");
+    opts.optopt("", "timeout", "Give up waiting for the database lock after SECONDS.", "SECONDS");
 
     let matches = opts.parse(&args).context("Failed to parse the command line options")?;
 
@@ -235,10 +475,15 @@ fn run() -> Result<(), Error> {
         .opt_str("s")
         .and_then(|name| parse_sort_method(&name))
         .unwrap_or(settings.sort_by);
-    settings.history_size = get_env::<usize>("FDB_HISTORY_SIZE", settings.history_size);
+    settings.max_age = get_env::<f32>("FDB_MAX_AGE", settings.max_age);
+    settings.check_exists = get_env::<bool>("FDB_CHECK_EXISTS", settings.check_exists) || matches.opt_present("e");
+    settings.match_mode = matches
+        .opt_str("match")
+        .and_then(|name| parse_match_mode(&name))
+        .unwrap_or(settings.match_mode);
 
     if matches.opt_present("u") {
-        settings.history_size = 0;
+        settings.max_age = 0.0;
     }
 
     if matches.opt_present("z") {
@@ -251,7 +496,8 @@ fn run() -> Result<(), Error> {
         return Ok(());
     }
 
-    let lock = Lock::new(&settings.db_path).context("Can't lock database")?;
+    let timeout = matches.opt_str("timeout").map(|s| parse_timeout(&s)).transpose()?;
+    let lock = Lock::new(&settings.db_path, timeout).context("Can't lock database")?;
 
     if matches.opt_present("q") {
         action = Some(Action::Query);
@@ -259,27 +505,44 @@ fn run() -> Result<(), Error> {
         action = Some(Action::Add);
     } else if matches.opt_present("d") {
         action = Some(Action::Delete);
+    } else if matches.opt_present("upgrade") {
+        action = Some(Action::Upgrade);
     }
 
-    if action.is_none() || matches.free.is_empty() {
+    let needs_args = !matches!(action, Some(Action::Upgrade) | None);
+    if action.is_none() || (needs_args && matches.free.is_empty()) {
         print_usage(&opts);
         return Ok(());
     }
 
     let action = action.unwrap();
-    let mut data: Vec<Item> = load_data(&settings.db_path).context("Can't load data")?;
 
     match action {
-        Action::Add => cmd_add(&settings, &mut data, &matches.free),
-        Action::Delete => cmd_delete(&mut data, &matches.free),
         Action::Query => {
-            return cmd_query(settings.sort_by, &mut data, &matches.free.join(".*"))
-                .context("Can't execute query").map_err(Into::into)
+            let mut data = load_data(&settings.db_path).context("Can't load data")?;
+            let matcher = Matcher::new(settings.match_mode, &matches.free).context("Can't build query matcher")?;
+            let pruned = cmd_query(settings.sort_by, &mut data, &matcher, settings.check_exists).context("Can't execute query")?;
+            if pruned {
+                save_data(&data, &settings.db_path).context("Can't save data")?;
+            }
+        }
+        Action::Add => {
+            let mut data = load_data(&settings.db_path).context("Can't load data")?;
+            cmd_add(&settings, &mut data, &matches.free);
+            save_data(&data, &settings.db_path).context("Can't save data")?;
+        }
+        Action::Delete => {
+            let mut data = load_data(&settings.db_path).context("Can't load data")?;
+            cmd_delete(&mut data, &matches.free);
+            save_data(&data, &settings.db_path).context("Can't save data")?;
+        }
+        Action::Upgrade => {
+            // load_data already migrated data; rewrite it to persist the upgrade
+            let data = load_data(&settings.db_path).context("Can't load data")?;
+            save_data(&data, &settings.db_path).context("Can't save data")?;
         }
     }
 
-    save_data(&data, &settings.db_path).context("Can't save data")?;
-
     drop(lock);
     Ok(())
 }
@@ -292,3 +555,44 @@ fn main() {
         process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyword_match_requires_order_and_basename() {
+        assert!(match_keywords(&["foo".to_string(), "bar".to_string()], "/foo/bar"));
+        assert!(!match_keywords(&["bar".to_string(), "foo".to_string()], "/foo/bar"));
+        // last keyword must land in the basename
+        assert!(!match_keywords(&["foo".to_string()], "/foo/bar"));
+        assert!(match_keywords(&["bar".to_string()], "/foo/bar"));
+    }
+
+    #[test]
+    fn keyword_match_is_smart_case() {
+        assert!(match_keywords(&["FOO".to_string()], "/bar/FOO"));
+        assert!(!match_keywords(&["FOO".to_string()], "/bar/foo"));
+        assert!(match_keywords(&["foo".to_string()], "/bar/FOO"));
+    }
+
+    #[test]
+    fn keyword_match_does_not_panic_on_case_folding_that_changes_byte_length() {
+        // Turkish dotted capital I (2 bytes) lowercases to 3 bytes via to_lowercase()
+        assert!(match_keywords(&["ab".to_string(), "c".to_string()], "/home/\u{130}ab\u{dc}c"));
+    }
+
+    #[test]
+    fn timeout_rejects_negative_infinite_and_nan() {
+        assert!(parse_timeout("5").is_ok());
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("inf").is_err());
+        assert!(parse_timeout("nan").is_err());
+    }
+
+    #[test]
+    fn migrate_rejects_a_newer_format_version_distinguishably() {
+        let err = migrate(FORMAT_VERSION + 1, &[]).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedFormatVersion>().is_some());
+    }
+}